@@ -0,0 +1,442 @@
+//! DNSCrypt v2 transport: certificate minting/rotation and the UDP/TCP
+//! listeners that decrypt incoming queries, hand them to `Handler`, and
+//! re-encrypt the answers.
+//!
+//! See <https://dnscrypt.info/protocol> for the wire formats implemented
+//! here.
+
+use std::{
+    net::SocketAddr,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305, XNonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha512};
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::*;
+use x25519_dalek::{PublicKey as XPublicKey, StaticSecret as XSecret};
+use xsalsa20poly1305::{aead::Aead as _, XSalsa20Poly1305};
+
+use hickory_server::server::Protocol;
+
+use crate::handler::Handler;
+
+const CERT_MAGIC: &[u8; 4] = b"DNSC";
+const CERT_ROTATE_EVERY: std::time::Duration = std::time::Duration::from_secs(6 * 3600);
+const CERT_VALIDITY: u32 = 24 * 3600;
+const PADDED_BLOCK: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("malformed DNSCrypt query")]
+    Malformed,
+    #[error("client magic did not match any known certificate")]
+    UnknownClientMagic,
+    #[error("decryption failed")]
+    DecryptionFailed,
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// The cipher negotiated by the short-term certificate's `es-version`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EsVersion {
+    XSalsa20Poly1305,
+    XChaCha20Poly1305,
+}
+
+impl EsVersion {
+    fn wire(self) -> [u8; 2] {
+        match self {
+            EsVersion::XSalsa20Poly1305 => [0x00, 0x01],
+            EsVersion::XChaCha20Poly1305 => [0x00, 0x02],
+        }
+    }
+}
+
+/// The server's long-term Ed25519 provider key pair, used only to sign
+/// short-term certificates.
+pub struct ProviderKeyPair {
+    signing_key: SigningKey,
+    pub verifying_key: VerifyingKey,
+}
+
+impl ProviderKeyPair {
+    pub fn generate() -> Self {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let verifying_key = signing_key.verifying_key();
+        ProviderKeyPair {
+            signing_key,
+            verifying_key,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let signing_key = SigningKey::from_bytes(bytes);
+        let verifying_key = signing_key.verifying_key();
+        ProviderKeyPair {
+            signing_key,
+            verifying_key,
+        }
+    }
+}
+
+/// A short-term certificate: an X25519 key used for the actual query/response
+/// encryption, signed by the provider's long-term key and published as a TXT
+/// record so clients can fetch and verify it.
+pub struct ShortTermCert {
+    pub serial: u32,
+    pub es_version: EsVersion,
+    pub client_magic: [u8; 8],
+    pub ts_start: u32,
+    pub ts_end: u32,
+    resolver_pk: XPublicKey,
+    resolver_sk: XSecret,
+    signature: Signature,
+}
+
+impl ShortTermCert {
+    fn mint(provider: &ProviderKeyPair, serial: u32, es_version: EsVersion) -> Self {
+        let resolver_sk = XSecret::random_from_rng(OsRng);
+        let resolver_pk = XPublicKey::from(&resolver_sk);
+
+        let mut client_magic = [0u8; 8];
+        client_magic.copy_from_slice(&Sha512::digest(resolver_pk.as_bytes())[..8]);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_secs() as u32;
+        let ts_start = now;
+        let ts_end = now + CERT_VALIDITY;
+
+        let signed = Self::signed_payload(&resolver_pk, &client_magic, serial, ts_start, ts_end);
+        let signature = provider.signing_key.sign(&signed);
+
+        ShortTermCert {
+            serial,
+            es_version,
+            client_magic,
+            ts_start,
+            ts_end,
+            resolver_pk,
+            resolver_sk,
+            signature,
+        }
+    }
+
+    fn signed_payload(
+        resolver_pk: &XPublicKey,
+        client_magic: &[u8; 8],
+        serial: u32,
+        ts_start: u32,
+        ts_end: u32,
+    ) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(48);
+        buf.extend_from_slice(resolver_pk.as_bytes());
+        buf.extend_from_slice(client_magic);
+        buf.extend_from_slice(&serial.to_be_bytes());
+        buf.extend_from_slice(&ts_start.to_be_bytes());
+        buf.extend_from_slice(&ts_end.to_be_bytes());
+        buf
+    }
+
+    fn is_valid_now(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_secs() as u32;
+        now >= self.ts_start && now < self.ts_end
+    }
+
+    /// Encode the certificate in the wire format published under the
+    /// provider name's TXT record: `DNSC` magic, es-version, signature,
+    /// resolver public key, client-magic, serial, then the validity window.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(124);
+        buf.extend_from_slice(CERT_MAGIC);
+        buf.extend_from_slice(&self.es_version.wire());
+        buf.extend_from_slice(&self.signature.to_bytes());
+        buf.extend_from_slice(&Self::signed_payload(
+            &self.resolver_pk,
+            &self.client_magic,
+            self.serial,
+            self.ts_start,
+            self.ts_end,
+        ));
+        buf
+    }
+
+    /// DNSCrypt certificates are raw binary in the TXT RDATA, not text;
+    /// build the rdata straight from the encoded bytes instead of routing
+    /// them through a `String` (TXT character-strings are byte blobs, not
+    /// UTF-8 text).
+    pub fn encode_as_txt(&self) -> hickory_server::proto::rr::rdata::TXT {
+        hickory_server::proto::rr::rdata::TXT::from_bytes(vec![self.encode().as_slice()])
+    }
+}
+
+/// Owns the current short-term certificate and rotates it on a timer.
+pub struct CertManager {
+    provider: ProviderKeyPair,
+    current: RwLock<ShortTermCert>,
+    es_version: EsVersion,
+    next_serial: std::sync::atomic::AtomicU32,
+}
+
+impl std::fmt::Debug for CertManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertManager").finish_non_exhaustive()
+    }
+}
+
+impl CertManager {
+    pub fn new(provider: ProviderKeyPair, es_version: EsVersion) -> Self {
+        let current = ShortTermCert::mint(&provider, 1, es_version);
+        CertManager {
+            provider,
+            current: RwLock::new(current),
+            es_version,
+            next_serial: std::sync::atomic::AtomicU32::new(2),
+        }
+    }
+
+    pub fn current(&self) -> std::sync::RwLockReadGuard<'_, ShortTermCert> {
+        self.current.read().unwrap()
+    }
+
+    fn rotate(&self) {
+        let serial = self
+            .next_serial
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let fresh = ShortTermCert::mint(&self.provider, serial, self.es_version);
+        *self.current.write().unwrap() = fresh;
+        info!("rotated DNSCrypt short-term certificate, serial {serial}");
+    }
+
+}
+
+/// Spawn the background task that re-mints the short-term certificate every
+/// `CERT_ROTATE_EVERY`.
+pub fn spawn_cert_rotation(certs: std::sync::Arc<CertManager>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(CERT_ROTATE_EVERY);
+        ticker.tick().await; // first tick fires immediately; the initial cert from `new` covers it
+        loop {
+            ticker.tick().await;
+            certs.rotate();
+        }
+    });
+}
+
+fn pad_to_block(mut buf: Vec<u8>) -> Vec<u8> {
+    buf.push(0x80);
+    while buf.len() % PADDED_BLOCK != 0 {
+        buf.push(0x00);
+    }
+    buf
+}
+
+fn unpad(buf: &[u8]) -> Result<&[u8], Error> {
+    let trimmed = buf
+        .iter()
+        .rposition(|&b| b != 0x00)
+        .map(|i| &buf[..=i])
+        .ok_or(Error::Malformed)?;
+    match trimmed.split_last() {
+        Some((0x80, rest)) => Ok(rest),
+        _ => Err(Error::Malformed),
+    }
+}
+
+/// Parsed fields common to every DNSCrypt query, regardless of transport.
+struct EncryptedQuery<'a> {
+    client_pk: XPublicKey,
+    client_nonce: [u8; 12],
+    ciphertext: &'a [u8],
+}
+
+fn parse_query(cert: &ShortTermCert, packet: &[u8]) -> Result<EncryptedQuery<'_>, Error> {
+    if packet.len() < 8 + 32 + 12 {
+        return Err(Error::Malformed);
+    }
+    if &packet[..8] != cert.client_magic {
+        return Err(Error::UnknownClientMagic);
+    }
+    let mut client_pk_bytes = [0u8; 32];
+    client_pk_bytes.copy_from_slice(&packet[8..40]);
+    let mut client_nonce = [0u8; 12];
+    client_nonce.copy_from_slice(&packet[40..52]);
+    Ok(EncryptedQuery {
+        client_pk: XPublicKey::from(client_pk_bytes),
+        client_nonce,
+        ciphertext: &packet[52..],
+    })
+}
+
+fn full_nonce(client_nonce: &[u8; 12], server_nonce: &[u8; 12]) -> [u8; 24] {
+    let mut nonce = [0u8; 24];
+    nonce[..12].copy_from_slice(client_nonce);
+    nonce[12..].copy_from_slice(server_nonce);
+    nonce
+}
+
+fn decrypt(
+    es_version: EsVersion,
+    shared_key: &[u8; 32],
+    nonce: &[u8; 24],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    match es_version {
+        EsVersion::XSalsa20Poly1305 => {
+            let cipher = XSalsa20Poly1305::new_from_slice(shared_key).unwrap();
+            cipher
+                .decrypt(nonce.into(), ciphertext)
+                .map_err(|_| Error::DecryptionFailed)
+        }
+        EsVersion::XChaCha20Poly1305 => {
+            let cipher = XChaCha20Poly1305::new_from_slice(shared_key).unwrap();
+            cipher
+                .decrypt(XNonce::from_slice(nonce), ciphertext)
+                .map_err(|_| Error::DecryptionFailed)
+        }
+    }
+}
+
+fn encrypt(
+    es_version: EsVersion,
+    shared_key: &[u8; 32],
+    nonce: &[u8; 24],
+    plaintext: &[u8],
+) -> Vec<u8> {
+    match es_version {
+        EsVersion::XSalsa20Poly1305 => {
+            let cipher = XSalsa20Poly1305::new_from_slice(shared_key).unwrap();
+            cipher.encrypt(nonce.into(), plaintext).unwrap()
+        }
+        EsVersion::XChaCha20Poly1305 => {
+            let cipher = XChaCha20Poly1305::new_from_slice(shared_key).unwrap();
+            cipher.encrypt(XNonce::from_slice(nonce), plaintext).unwrap()
+        }
+    }
+}
+
+/// Decrypt one DNSCrypt packet, run the contained query through `handler`,
+/// and return the encrypted response ready to write back to the client.
+async fn process_packet(
+    certs: &CertManager,
+    handler: &Handler,
+    src: SocketAddr,
+    protocol: Protocol,
+    packet: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    let cert = certs.current();
+    if !cert.is_valid_now() || cert.client_magic.as_slice() != &packet[..packet.len().min(8)] {
+        return Err(Error::UnknownClientMagic);
+    }
+    let query = parse_query(&cert, packet)?;
+    let shared_key = *cert.resolver_sk.diffie_hellman(&query.client_pk).as_bytes();
+
+    let mut server_nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut server_nonce);
+    let decrypt_nonce = full_nonce(&query.client_nonce, &[0u8; 12]);
+    let padded = decrypt(cert.es_version, &shared_key, &decrypt_nonce, query.ciphertext)?;
+    let dns_query = unpad(&padded)?;
+
+    let Some(dns_response) = handler
+        .handle_wire_message(dns_query, src, protocol, false)
+        .await
+    else {
+        return Ok(None);
+    };
+
+    let response_nonce = full_nonce(&query.client_nonce, &server_nonce);
+    let padded_response = pad_to_block(dns_response);
+    let ciphertext = encrypt(cert.es_version, &shared_key, &response_nonce, &padded_response);
+
+    let mut out = Vec::with_capacity(12 + ciphertext.len());
+    out.extend_from_slice(&query.client_nonce);
+    out.extend_from_slice(&server_nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(Some(out))
+}
+
+/// Run the UDP DNSCrypt listener: one encrypted datagram in, one out.
+pub async fn run_udp(
+    socket: UdpSocket,
+    handler: Handler,
+    certs: std::sync::Arc<CertManager>,
+) -> Result<(), Error> {
+    let mut buf = [0u8; 4096];
+    loop {
+        let (len, src) = socket.recv_from(&mut buf).await?;
+        match process_packet(
+            &certs,
+            &handler,
+            src,
+            Protocol::Udp,
+            &buf[..len],
+        )
+        .await
+        {
+            Ok(Some(response)) => {
+                if let Err(e) = socket.send_to(&response, src).await {
+                    warn!("failed to send DNSCrypt UDP response to {src}: {e}");
+                }
+            }
+            Ok(None) => {}
+            Err(e) => debug!("dropping DNSCrypt UDP packet from {src}: {e}"),
+        }
+    }
+}
+
+/// Run the TCP DNSCrypt listener: each connection carries one 2-byte-length
+/// prefixed packet per query, same as plain DNS-over-TCP.
+pub async fn run_tcp(
+    listener: TcpListener,
+    handler: Handler,
+    certs: std::sync::Arc<CertManager>,
+) -> Result<(), Error> {
+    loop {
+        let (stream, src) = listener.accept().await?;
+        let handler = handler.clone();
+        let certs = certs.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_tcp_connection(stream, src, handler, certs).await {
+                debug!("DNSCrypt TCP connection from {src} ended: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_tcp_connection(
+    mut stream: TcpStream,
+    src: SocketAddr,
+    handler: Handler,
+    certs: std::sync::Arc<CertManager>,
+) -> Result<(), Error> {
+    let mut len_buf = [0u8; 2];
+    stream.read_exact(&mut len_buf).await?;
+    let len = u16::from_be_bytes(len_buf) as usize;
+    let mut packet = vec![0u8; len];
+    stream.read_exact(&mut packet).await?;
+
+    if let Some(response) = process_packet(
+        &certs,
+        &handler,
+        src,
+        Protocol::Tcp,
+        &packet,
+    )
+    .await?
+    {
+        stream.write_all(&(response.len() as u16).to_be_bytes()).await?;
+        stream.write_all(&response).await?;
+    }
+    Ok(())
+}
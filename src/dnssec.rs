@@ -0,0 +1,281 @@
+//! Online DNSSEC signing of the dynamically generated answers.
+//!
+//! Every answer `Handler` produces is synthesized per-request (myip,
+//! random, timestamp, ...), so a static zone signer doesn't fit: instead,
+//! whenever a query carries the EDNS DO bit, each RRset is signed on the
+//! fly against one or more zone signing keys loaded at startup. See RFC
+//! 4034 for the RRSIG/DNSKEY/NSEC wire formats and the canonicalization and
+//! key-tag rules implemented here.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hickory_server::proto::rr::{rdata::DNSKEY, rdata::NSEC, Name, RData, Record, RecordType};
+use hickory_server::proto::serialize::binary::{BinEncodable, BinEncoder};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unreadable key file {0}: {1}")]
+    Unreadable(String, std::io::Error),
+    #[error("unrecognized --dnssec-zsk entry {0:?}, expected \"<algorithm>:<path>\"")]
+    BadSpec(String),
+    #[error("unsupported DNSSEC algorithm {0:?}")]
+    UnsupportedAlgorithm(String),
+    #[error("malformed key material in {0}")]
+    BadKey(String),
+}
+
+/// The DNSSEC algorithm numbers (RFC 8624) this server can sign with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    EcdsaP256Sha256,
+    Ed25519,
+}
+
+impl Algorithm {
+    fn value(self) -> u8 {
+        match self {
+            Algorithm::EcdsaP256Sha256 => 13,
+            Algorithm::Ed25519 => 15,
+        }
+    }
+}
+
+enum Signer {
+    EcdsaP256(p256::ecdsa::SigningKey),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+/// One zone signing key: its public key material (as published in the
+/// DNSKEY RRset) plus whatever is needed to produce signatures.
+pub struct ZoneSigningKey {
+    pub algorithm: Algorithm,
+    pub key_tag: u16,
+    public_key: Vec<u8>,
+    signer: Signer,
+}
+
+impl std::fmt::Debug for ZoneSigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZoneSigningKey")
+            .field("algorithm", &self.algorithm)
+            .field("key_tag", &self.key_tag)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ZoneSigningKey {
+    /// Parse a `--dnssec-zsk` entry of the form `<algorithm>:<path>`, where
+    /// `path` holds a PEM- or DER-encoded private key matching `algorithm`.
+    pub fn load(spec: &str) -> Result<Self, Error> {
+        let (algorithm_name, path) = spec
+            .split_once(':')
+            .ok_or_else(|| Error::BadSpec(spec.to_string()))?;
+
+        let algorithm = match algorithm_name.to_ascii_lowercase().as_str() {
+            "ecdsap256sha256" => Algorithm::EcdsaP256Sha256,
+            "ed25519" => Algorithm::Ed25519,
+            other => return Err(Error::UnsupportedAlgorithm(other.to_string())),
+        };
+
+        let der = read_key_der(path)?;
+
+        let (signer, public_key) = match algorithm {
+            Algorithm::EcdsaP256Sha256 => {
+                let signing_key = p256::ecdsa::SigningKey::from_pkcs8_der(&der)
+                    .or_else(|_| p256::ecdsa::SigningKey::from_sec1_der(&der))
+                    .map_err(|_| Error::BadKey(path.to_string()))?;
+                let point = signing_key.verifying_key().to_encoded_point(false);
+                // DNSKEY public key for ECDSA is the uncompressed point with
+                // the leading 0x04 tag stripped (RFC 6605).
+                let public_key = point.as_bytes()[1..].to_vec();
+                (Signer::EcdsaP256(signing_key), public_key)
+            }
+            Algorithm::Ed25519 => {
+                let bytes: [u8; 32] = der
+                    .get(der.len().saturating_sub(32)..)
+                    .and_then(|s| s.try_into().ok())
+                    .ok_or_else(|| Error::BadKey(path.to_string()))?;
+                let signing_key = ed25519_dalek::SigningKey::from_bytes(&bytes);
+                let public_key = signing_key.verifying_key().to_bytes().to_vec();
+                (Signer::Ed25519(signing_key), public_key)
+            }
+        };
+
+        let key_tag = compute_key_tag(algorithm, &public_key);
+
+        Ok(ZoneSigningKey {
+            algorithm,
+            key_tag,
+            public_key,
+            signer,
+        })
+    }
+
+    pub fn dnskey_rdata(&self) -> DNSKEY {
+        DNSKEY::new(
+            true,  // zone key
+            false, // not a secure entry point key
+            false, // not revoked
+            hickory_server::proto::rr::dnssec::Algorithm::from_u8(self.algorithm.value()),
+            self.public_key.clone(),
+        )
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match &self.signer {
+            Signer::EcdsaP256(key) => {
+                use p256::ecdsa::signature::Signer as _;
+                let sig: p256::ecdsa::Signature = key.sign(data);
+                sig.to_bytes().to_vec()
+            }
+            Signer::Ed25519(key) => {
+                use ed25519_dalek::Signer as _;
+                key.sign(data).to_bytes().to_vec()
+            }
+        }
+    }
+}
+
+fn read_key_der(path: &str) -> Result<Vec<u8>, Error> {
+    let bytes =
+        std::fs::read(path).map_err(|e| Error::Unreadable(path.to_string(), e))?;
+    if bytes.starts_with(b"-----BEGIN") {
+        let mut reader = std::io::BufReader::new(bytes.as_slice());
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(rustls_pemfile::Item::PKCS8Key(key))) => Ok(key),
+            Ok(Some(rustls_pemfile::Item::ECKey(key))) => Ok(key),
+            _ => Err(Error::BadKey(path.to_string())),
+        }
+    } else {
+        Ok(bytes)
+    }
+}
+
+/// RFC 4034 Appendix B key tag algorithm, computed over the DNSKEY RDATA.
+fn compute_key_tag(algorithm: Algorithm, public_key: &[u8]) -> u16 {
+    let mut rdata = Vec::with_capacity(4 + public_key.len());
+    rdata.extend_from_slice(&256u16.to_be_bytes()); // flags: zone key
+    rdata.push(3); // protocol, always 3
+    rdata.push(algorithm.value());
+    rdata.extend_from_slice(public_key);
+
+    let mut ac: u32 = 0;
+    for (i, &byte) in rdata.iter().enumerate() {
+        if i % 2 == 0 {
+            ac += (byte as u32) << 8;
+        } else {
+            ac += byte as u32;
+        }
+    }
+    ac += (ac >> 16) & 0xffff;
+    (ac & 0xffff) as u16
+}
+
+fn now_secs() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs() as u32
+}
+
+fn canonical_name_wire(name: &Name) -> Vec<u8> {
+    let lower = name.to_lowercase();
+    let mut buf = Vec::new();
+    let mut encoder = BinEncoder::new(&mut buf);
+    encoder.set_canonical_names(true);
+    lower.emit(&mut encoder).expect("name always encodes");
+    buf
+}
+
+fn record_wire_for_signing(record: &Record) -> Vec<u8> {
+    // owner | type | class | original-ttl | rdlength | rdata, all with
+    // compression disabled and the owner name lowercased, per RFC 4034
+    // section 3.1.8.1.
+    let mut buf = canonical_name_wire(record.name());
+    buf.extend_from_slice(&u16::from(record.record_type()).to_be_bytes());
+    buf.extend_from_slice(&u16::from(record.dns_class()).to_be_bytes());
+    buf.extend_from_slice(&record.ttl().to_be_bytes());
+
+    let mut rdata_buf = Vec::new();
+    if let Some(rdata) = record.data() {
+        let mut encoder = BinEncoder::new(&mut rdata_buf);
+        encoder.set_canonical_names(true);
+        let _ = rdata.emit(&mut encoder);
+    }
+    buf.extend_from_slice(&(rdata_buf.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&rdata_buf);
+    buf
+}
+
+/// Sign one RRset (all same owner/type/class) and return the RRSIG record
+/// to append alongside it.
+pub fn sign_rrset(
+    key: &ZoneSigningKey,
+    signer_name: &Name,
+    covered_type: RecordType,
+    rrset: &[Record],
+    validity_secs: u32,
+) -> Record {
+    let owner = rrset[0].name().clone();
+    let original_ttl = rrset[0].ttl();
+    let labels = owner.num_labels();
+
+    let inception = now_secs();
+    let expiration = inception.saturating_add(validity_secs);
+
+    let mut sorted: Vec<&Record> = rrset.iter().collect();
+    sorted.sort_by(|a, b| {
+        let a = record_wire_for_signing(a);
+        let b = record_wire_for_signing(b);
+        a.cmp(&b)
+    });
+
+    let mut rrsig_rdata_prefix = Vec::new();
+    rrsig_rdata_prefix.extend_from_slice(&u16::from(covered_type).to_be_bytes());
+    rrsig_rdata_prefix.push(key.algorithm.value());
+    rrsig_rdata_prefix.push(labels as u8);
+    rrsig_rdata_prefix.extend_from_slice(&original_ttl.to_be_bytes());
+    rrsig_rdata_prefix.extend_from_slice(&expiration.to_be_bytes());
+    rrsig_rdata_prefix.extend_from_slice(&inception.to_be_bytes());
+    rrsig_rdata_prefix.extend_from_slice(&key.key_tag.to_be_bytes());
+    rrsig_rdata_prefix.extend_from_slice(&canonical_name_wire(signer_name));
+
+    let mut signing_input = rrsig_rdata_prefix.clone();
+    for record in &sorted {
+        signing_input.extend_from_slice(&record_wire_for_signing(record));
+    }
+
+    let signature = key.sign(&signing_input);
+
+    let sig = hickory_server::proto::rr::rdata::SIG::new(
+        covered_type,
+        hickory_server::proto::rr::dnssec::Algorithm::from_u8(key.algorithm.value()),
+        labels as u8,
+        original_ttl,
+        expiration as i32,
+        inception as i32,
+        key.key_tag,
+        signer_name.clone(),
+        signature,
+    );
+
+    Record::from_rdata(owner, original_ttl, RData::SIG(sig))
+}
+
+/// Build a minimal NSEC record covering just `qname` itself (a "white lie"
+/// NSEC, not derived from an ordered zone), asserting no other types exist,
+/// so a validator accepts the denial rather than rejecting an unsigned
+/// NXDOMAIN/NODATA.
+pub fn synthesize_nsec(qname: &Name) -> Record {
+    // The next name after qname in the (fake) canonical ordering; prefixing
+    // qname with a `\000` label (a child of qname) keeps it syntactically
+    // after qname while staying within the zone.
+    let next = Name::from_labels(
+        std::iter::once("\u{0}".as_bytes().to_vec()).chain(qname.iter().map(|l| l.to_vec())),
+    )
+    .unwrap_or_else(|_| qname.clone());
+
+    let nsec = NSEC::new(next, vec![RecordType::RRSIG, RecordType::NSEC]);
+    Record::from_rdata(qname.clone(), 0, RData::NSEC(nsec))
+}
@@ -0,0 +1,169 @@
+//! Forwarding/recursive fallback: anything outside the known diagnostic
+//! zones gets forwarded to an upstream resolver instead of failing with
+//! ServFail, backed by a small bounded, TTL-aware cache.
+
+use std::{
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hickory_server::proto::{
+    op::{Message, MessageType, OpCode, Query},
+    rr::{LowerName, Name, RecordType},
+    rr::Record,
+    serialize::binary::{BinDecodable, BinEncodable},
+};
+use lru::LruCache;
+use rand::Rng;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tracing::*;
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("no forwarders configured")]
+    NoForwarders,
+    #[error("every configured forwarder failed")]
+    AllForwardersFailed,
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("timed out waiting for upstream")]
+    Timeout,
+}
+
+#[derive(Clone)]
+struct CacheEntry {
+    records: Vec<Record>,
+    rcode_is_nxdomain: bool,
+    inserted_at: Instant,
+    expires_at: Instant,
+}
+
+/// A bounded, LRU-evicted cache of upstream answers, keyed by
+/// `(qname, qtype)`. Positive and negative (NXDOMAIN/NODATA) answers are
+/// both cached; served TTLs count down in real time between lookups.
+pub struct ForwardCache {
+    entries: Mutex<LruCache<(LowerName, RecordType), CacheEntry>>,
+    negative_ttl: u32,
+}
+
+impl std::fmt::Debug for ForwardCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ForwardCache").finish_non_exhaustive()
+    }
+}
+
+impl ForwardCache {
+    pub fn new(max_entries: usize, negative_ttl: u32) -> Self {
+        ForwardCache {
+            entries: Mutex::new(LruCache::new(
+                std::num::NonZeroUsize::new(max_entries.max(1)).unwrap(),
+            )),
+            negative_ttl,
+        }
+    }
+
+    fn get(&self, name: &LowerName, rtype: RecordType) -> Option<(Vec<Record>, bool)> {
+        let mut cache = self.entries.lock().unwrap();
+        let key = (name.clone(), rtype);
+        let entry = cache.get(&key)?.clone();
+        let now = Instant::now();
+        if now >= entry.expires_at {
+            cache.pop(&key);
+            return None;
+        }
+        let elapsed = now.saturating_duration_since(entry.inserted_at).as_secs() as u32;
+        let records = entry
+            .records
+            .iter()
+            .cloned()
+            .map(|mut r| {
+                r.set_ttl(r.ttl().saturating_sub(elapsed));
+                r
+            })
+            .collect();
+        Some((records, entry.rcode_is_nxdomain))
+    }
+
+    fn put(&self, name: LowerName, rtype: RecordType, records: Vec<Record>, rcode_is_nxdomain: bool) {
+        let min_ttl = if records.is_empty() {
+            self.negative_ttl
+        } else {
+            records.iter().map(|r| r.ttl()).min().unwrap_or(self.negative_ttl)
+        };
+        let now = Instant::now();
+        let entry = CacheEntry {
+            records,
+            rcode_is_nxdomain,
+            inserted_at: now,
+            expires_at: now + Duration::from_secs(min_ttl as u64),
+        };
+        self.entries.lock().unwrap().put((name, rtype), entry);
+    }
+}
+
+fn build_query(name: &Name, rtype: RecordType) -> Message {
+    let mut message = Message::new();
+    message.set_id(rand::thread_rng().gen());
+    message.set_message_type(MessageType::Query);
+    message.set_op_code(OpCode::Query);
+    message.set_recursion_desired(true);
+    let mut query = Query::new();
+    query.set_name(name.clone());
+    query.set_query_type(rtype);
+    message.add_query(query);
+    message
+}
+
+async fn ask_one(forwarder: IpAddr, query: &Message) -> Result<Message, Error> {
+    let local = if forwarder.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect((forwarder, 53)).await?;
+    let bytes = query.to_bytes().map_err(|_| Error::AllForwardersFailed)?;
+    socket.send(&bytes).await?;
+
+    let mut buf = [0u8; 4096];
+    let len = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| Error::Timeout)??;
+    Message::from_bytes(&buf[..len]).map_err(|_| Error::AllForwardersFailed)
+}
+
+/// Forward `name`/`rtype` to the first forwarder that answers, trying each
+/// configured upstream in order, then cache and return the result.
+pub async fn forward(
+    forwarders: &[IpAddr],
+    cache: &ForwardCache,
+    name: &LowerName,
+    rtype: RecordType,
+) -> Result<(Vec<Record>, bool), Error> {
+    if let Some(cached) = cache.get(name, rtype) {
+        return Ok(cached);
+    }
+
+    if forwarders.is_empty() {
+        return Err(Error::NoForwarders);
+    }
+
+    let query = build_query(&Name::from(name.clone()), rtype);
+
+    for forwarder in forwarders {
+        match ask_one(*forwarder, &query).await {
+            Ok(response) => {
+                let is_nxdomain =
+                    response.response_code() == hickory_server::proto::op::ResponseCode::NXDomain;
+                let records: Vec<Record> = response.answers().to_vec();
+                cache.put(name.clone(), rtype, records.clone(), is_nxdomain);
+                return Ok((records, is_nxdomain));
+            }
+            Err(e) => {
+                debug!("forwarder {forwarder} failed: {e}");
+            }
+        }
+    }
+
+    Err(Error::AllForwardersFailed)
+}
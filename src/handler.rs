@@ -3,26 +3,36 @@ use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 
 use std::{
-    net::IpAddr,
+    io,
+    net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    time::Instant,
 };
+use lru::LruCache;
 use tracing::*;
 use hickory_server::{
     authority::MessageResponseBuilder,
     proto::rr::{rdata::TXT, LowerName, Name, RData, Record},
     proto::{
-        op::{Header, MessageType, OpCode, ResponseCode},
+        op::{Header, MessageRequest, MessageType, OpCode, ResponseCode},
+        serialize::binary::{BinDecodable, BinDecoder, BinEncoder},
         rr::RecordType,
     },
-    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+    server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo},
 };
 
 use hickory_server::proto::rr::rdata::soa::SOA;
 
+use crate::dnscrypt::CertManager;
+use crate::dnssec::{self, ZoneSigningKey};
+use crate::forward::{self, ForwardCache};
+#[cfg(feature = "metrics")]
+use crate::metrics::{Metrics, Proto};
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Invalid OpCode {0:}")]
@@ -35,6 +45,12 @@ pub enum Error {
     Io(#[from] std::io::Error),
 }
 
+/// Cap on the number of distinct qnames `jittered_ttl` tracks state for, so
+/// a flood of distinct names (e.g. `random123.myip.<domain>`-style queries)
+/// can't grow it without bound; the oldest-used name is evicted first, same
+/// as `ForwardCache`.
+const TTL_STATE_MAX_ENTRIES: usize = 10_000;
+
 /// DNS Request Handler
 #[derive(Clone, Debug)]
 pub struct Handler {
@@ -53,27 +69,63 @@ pub struct Handler {
     pub ttl: u32,
     pub ns_names: Vec<String>,
     pub soa_names: Vec<String>,
+    pub dnscrypt_cert_zone: Option<LowerName>,
+    pub dnscrypt_certs: Option<Arc<CertManager>>,
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Arc<Metrics>>,
+    pub ttl_jitter: bool,
+    pub ttl_jitter_low_water: u32,
+    pub ttl_jitter_floor: u32,
+    pub ttl_jitter_percent: u32,
+    ttl_state: Arc<Mutex<LruCache<Name, (u32, Instant)>>>,
+    domain_name: Name,
+    dnssec_keys: Vec<Arc<ZoneSigningKey>>,
+    dnssec_validity_secs: u32,
+    dnssec_set_ad: bool,
+    forwarders: Vec<IpAddr>,
+    forward_cache: Arc<ForwardCache>,
+    captive_portal: bool,
+    captive_portal_address: Option<IpAddr>,
+    captive_portal_ttl: u32,
+    captive_portal_allowed_suffixes: Vec<LowerName>,
+}
+
+/// The result of parsing an EDNS Client Subnet option.
+enum EdnsCsSubnet {
+    /// `FAMILY == 0` (normally sent with a zero source prefix; the prefix is
+    /// not checked): the client is ECS-aware but chose not to send a subnet
+    /// (RFC 7871 section 7.1.2).
+    Declined,
+    Network(ipnet::IpNet),
 }
 
-fn parse_ednscs_subnet(subnet: Vec<u8>) -> ipnet::IpNet {
+fn parse_ednscs_subnet(subnet: &[u8]) -> Result<EdnsCsSubnet, String> {
+    if subnet.len() < 3 {
+        return Err(format!(
+            "EDNS Client Subnet option too short to parse: {:?}",
+            subnet
+        ));
+    }
     let family = subnet[1];
     let prefix_length = subnet[2];
 
     if family == 0 {
-        // Spec say this shouldn't ever exist, but it does in the wild from some software.
+        // Spec says this shouldn't ever exist, but it does in the wild from some software.
         // I think the meaning is "I'm aware of EDNS-CS" but don't want to use it for this request.
-        todo!()
+        Ok(EdnsCsSubnet::Declined)
     } else if family == 1 {
-        let mut x = subnet;
+        let mut x = subnet.to_vec();
         x.resize(8, 0);
         let addr = ipnet::IpNet::new(
             std::net::IpAddr::V4(std::net::Ipv4Addr::new(x[4], x[5], x[6], x[7])),
             prefix_length,
         )
-        .unwrap();
-        return addr;
+        .map_err(|e| {
+            format!("invalid IPv4 prefix length {prefix_length} in EDNS Client Subnet: {e}")
+        })?;
+        Ok(EdnsCsSubnet::Network(addr))
     } else if family == 2 {
-        let mut x = subnet;
+        let mut x = subnet.to_vec();
         x.resize(20, 0);
         let x: Vec<u16> = x
             .chunks_exact(2)
@@ -85,10 +137,12 @@ fn parse_ednscs_subnet(subnet: Vec<u8>) -> ipnet::IpNet {
             )),
             prefix_length,
         )
-        .unwrap();
-        return addr;
+        .map_err(|e| {
+            format!("invalid IPv6 prefix length {prefix_length} in EDNS Client Subnet: {e}")
+        })?;
+        Ok(EdnsCsSubnet::Network(addr))
     } else {
-        todo!("Bad ednscs data: {:?}", subnet);
+        Err(format!("unsupported EDNS Client Subnet family {family}"))
     }
 }
 
@@ -102,11 +156,11 @@ impl Handler {
             counter_zone: LowerName::from(Name::from_str(&format!("counter.{domain}")).unwrap()),
             myip_zone: LowerName::from(Name::from_str(&format!("myip.{domain}")).unwrap()),
             myport_zone: LowerName::from(Name::from_str(&format!("myport.{domain}")).unwrap()),
-            myaddr_zone: LowerName::from(Name::from_str(&format!("myaddr.{domain}")).unwrap())),
-            help_zone: LowerName::from(Name::from_str(&format!("help.{domain}")).unwrap())),
-            random_zone: LowerName::from(Name::from_str(&format!("random.{domain}")).unwrap())),
-            edns_zone: LowerName::from(Name::from_str(&format!("edns.{domain}")).unwrap())),
-            ednscs_zone: LowerName::from(Name::from_str(&format!("edns-cs.{domain}")).unwrap())),
+            myaddr_zone: LowerName::from(Name::from_str(&format!("myaddr.{domain}")).unwrap()),
+            help_zone: LowerName::from(Name::from_str(&format!("help.{domain}")).unwrap()),
+            random_zone: LowerName::from(Name::from_str(&format!("random.{domain}")).unwrap()),
+            edns_zone: LowerName::from(Name::from_str(&format!("edns.{domain}")).unwrap()),
+            ednscs_zone: LowerName::from(Name::from_str(&format!("edns-cs.{domain}")).unwrap()),
             timestamp_zone: LowerName::from(
                 Name::from_str(&format!("timestamp.{domain}")).unwrap(),
             ),
@@ -116,25 +170,147 @@ impl Handler {
             ttl: options.ttl,
             ns_names: options.ns_records.clone(),
             soa_names: options.soa_names.clone(),
+            dnscrypt_cert_zone: None,
+            dnscrypt_certs: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            ttl_jitter: options.ttl_jitter,
+            ttl_jitter_low_water: options.ttl_jitter_low_water,
+            ttl_jitter_floor: options.ttl_jitter_floor,
+            ttl_jitter_percent: options.ttl_jitter_percent,
+            ttl_state: Arc::new(Mutex::new(LruCache::new(
+                std::num::NonZeroUsize::new(TTL_STATE_MAX_ENTRIES).unwrap(),
+            ))),
+            domain_name: Name::from_str(domain).unwrap(),
+            dnssec_keys: Vec::new(),
+            dnssec_validity_secs: options.dnssec_signature_validity_secs,
+            dnssec_set_ad: options.dnssec_set_ad,
+            forwarders: Vec::new(),
+            forward_cache: Arc::new(ForwardCache::new(
+                options.forward_cache_size,
+                options.forward_negative_ttl,
+            )),
+            captive_portal: options.captive_portal,
+            captive_portal_address: options.captive_portal_address,
+            captive_portal_ttl: options.captive_portal_ttl,
+            captive_portal_allowed_suffixes: options
+                .captive_portal_allowed_suffixes
+                .iter()
+                .map(|s| LowerName::from(Name::from_str(s).unwrap()))
+                .collect(),
         }
     }
 
+    /// Attach zone signing keys so DO-bit queries get signed answers and
+    /// the DNSKEY RRset is published at the zone apex.
+    pub fn with_dnssec(mut self, keys: Vec<Arc<ZoneSigningKey>>) -> Self {
+        self.dnssec_keys = keys;
+        self
+    }
+
+    /// Attach DNSCrypt certificate serving to this handler, so that the
+    /// provider's short-term certificate is published as a TXT record under
+    /// `provider_name`, the same way DNSCrypt-capable resolvers expect to
+    /// fetch it over plain DNS.
+    pub fn with_dnscrypt(mut self, provider_name: &str, certs: Arc<CertManager>) -> Self {
+        self.dnscrypt_cert_zone = Some(LowerName::from(Name::from_str(provider_name).unwrap()));
+        self.dnscrypt_certs = Some(certs);
+        self
+    }
+
+    /// Attach a shared Prometheus registry so every request path records
+    /// into the same series.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Forward names outside every known diagnostic zone to `forwarders`
+    /// (tried in order) instead of answering NXDOMAIN, caching the results.
+    pub fn with_forwarding(mut self, forwarders: Vec<IpAddr>, cache_size: usize, negative_ttl: u32) -> Self {
+        self.forwarders = forwarders;
+        self.forward_cache = Arc::new(ForwardCache::new(cache_size, negative_ttl));
+        self
+    }
+
     async fn increment_counter(&self) {
         self.counter.fetch_add(1, Ordering::SeqCst);
     }
 
-    fn build_response(&self, request: &Request, records: Vec<Record>) -> MessageResponse {
+    fn build_response(&self, request: &Request, mut records: Vec<Record>) -> MessageResponse {
+        let do_bit = self.dnssec_do_requested(request);
+        if do_bit && !records.is_empty() {
+            let covered_type = records[0].record_type();
+            let mut rrsigs = Vec::with_capacity(self.dnssec_keys.len());
+            for key in &self.dnssec_keys {
+                rrsigs.push(dnssec::sign_rrset(
+                    key,
+                    &self.domain_name,
+                    covered_type,
+                    &records,
+                    self.dnssec_validity_secs,
+                ));
+            }
+            records.extend(rrsigs);
+        }
+
         let builder = MessageResponseBuilder::from_message_request(request);
         let mut header = Header::response_from_request(request.header());
         header.set_authoritative(true);
+        if do_bit && self.dnssec_set_ad {
+            header.set_authentic_data(true);
+        }
         builder.build(header, records.iter(), &[], &[], &[])
     }
 
+    fn dnssec_do_requested(&self, request: &Request) -> bool {
+        !self.dnssec_keys.is_empty()
+            && request
+                .edns()
+                .map(|edns| edns.dnssec_ok())
+                .unwrap_or(false)
+    }
+
     fn create_records(&self, request: &Request, rdata: RData, ttl: Option<u32>) -> Vec<Record> {
-        let ttl = ttl.unwrap_or(self.ttl);
+        let ttl = match ttl {
+            Some(ttl) => ttl,
+            None if self.ttl_jitter => self.jittered_ttl(request.query().name().into()),
+            None => self.ttl,
+        };
         vec![Record::from_rdata(request.query().name().into(), ttl, rdata)]
     }
 
+    /// Compute a decreasing, jittered TTL for `name`: the TTL handed out the
+    /// first time a name is seen counts down in real time, and once it drops
+    /// below `ttl_jitter_low_water` it gets +/- `ttl_jitter_percent` of
+    /// uniform noise, clamped to `ttl_jitter_floor`.
+    fn jittered_ttl(&self, name: Name) -> u32 {
+        let now = Instant::now();
+        let (initial_ttl, first_seen) = {
+            let mut state = self.ttl_state.lock().unwrap();
+            match state.get(&name) {
+                Some(&v) => v,
+                None => {
+                    let v = (self.ttl, now);
+                    state.put(name, v);
+                    v
+                }
+            }
+        };
+
+        let elapsed = now.duration_since(first_seen).as_secs() as u32;
+        let remaining = initial_ttl.saturating_sub(elapsed);
+
+        if remaining >= self.ttl_jitter_low_water {
+            return remaining.max(self.ttl_jitter_floor);
+        }
+
+        let jitter_range = ((remaining * self.ttl_jitter_percent) / 100).max(1) as i64;
+        let jitter = thread_rng().gen_range(-jitter_range..=jitter_range);
+        (remaining as i64 + jitter).max(self.ttl_jitter_floor as i64) as u32
+    }
+
     async fn do_handle_request_myip<R: ResponseHandler>(
         &self,
         request: &Request,
@@ -242,18 +418,38 @@ impl Handler {
         request: &Request,
         mut responder: R,
     ) -> Result<ResponseInfo, Error> {
-        let ednscs_option = request
-            .edns()
-            .unwrap()
-            .options()
-            .get(hickory_server::proto::rr::rdata::opt::EdnsCode::Subnet)
-            .unwrap()
-            .try_into()
-            .unwrap();
-            
-        let ednscs: Vec<u8> = ednscs_option;
-        let net = parse_ednscs_subnet(ednscs);
-        let rdata = RData::TXT(TXT::new(vec![net.to_string()]));
+        let option_bytes: Option<Vec<u8>> = request.edns().and_then(|edns| {
+            edns.options()
+                .get(hickory_server::proto::rr::rdata::opt::EdnsCode::Subnet)
+        }).and_then(|option| option.try_into().ok());
+
+        let rdata = match option_bytes {
+            None => RData::TXT(TXT::new(vec![
+                "no EDNS Client Subnet option present in this request".to_string(),
+            ])),
+            Some(bytes) => match parse_ednscs_subnet(&bytes) {
+                Err(e) => RData::TXT(TXT::new(vec![e])),
+                Ok(EdnsCsSubnet::Declined) => RData::TXT(TXT::new(vec![
+                    "client is EDNS Client Subnet aware but declined to send a subnet"
+                        .to_string(),
+                ])),
+                // Use the masked network address, not the client-supplied
+                // `net.addr()`: non-conforming clients can set host bits
+                // beyond the source prefix, which would otherwise leak into
+                // the synthesized answer.
+                Ok(EdnsCsSubnet::Network(net)) => match (request.query().query_type(), net.network())
+                {
+                    (RecordType::A, IpAddr::V4(ipv4)) => {
+                        RData::A(hickory_server::proto::rr::rdata::A(ipv4))
+                    }
+                    (RecordType::AAAA, IpAddr::V6(ipv6)) => {
+                        RData::AAAA(hickory_server::proto::rr::rdata::AAAA(ipv6))
+                    }
+                    _ => RData::TXT(TXT::new(vec![net.to_string()])),
+                },
+            },
+        };
+
         let records = self.create_records(request, rdata, None);
         let response = self.build_response(request, records);
         Ok(responder.send_response(response).await?)
@@ -298,6 +494,75 @@ impl Handler {
         Ok(responder.send_response(response).await?)
     }
 
+    async fn do_handle_request_dnscrypt_cert<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut responder: R,
+    ) -> Result<ResponseInfo, Error> {
+        let certs = self
+            .dnscrypt_certs
+            .as_ref()
+            .expect("dnscrypt_cert_zone set without dnscrypt_certs");
+        let rdata = RData::TXT(certs.current().encode_as_txt());
+        let records = self.create_records(request, rdata, Some(60));
+        let response = self.build_response(request, records);
+        Ok(responder.send_response(response).await?)
+    }
+
+    /// Answer an A/AAAA query with the fixed captive-portal address,
+    /// regardless of QNAME, or NODATA+SOA when the query's address family
+    /// doesn't match the one configured.
+    async fn do_handle_request_captive_portal<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut responder: R,
+    ) -> Result<ResponseInfo, Error> {
+        let matching_address = self.captive_portal_address.filter(|addr| {
+            matches!(
+                (request.query().query_type(), addr),
+                (RecordType::A, IpAddr::V4(_)) | (RecordType::AAAA, IpAddr::V6(_))
+            )
+        });
+
+        let records = match matching_address {
+            Some(IpAddr::V4(ipv4)) => self.create_records(
+                request,
+                RData::A(hickory_server::proto::rr::rdata::A(ipv4)),
+                Some(self.captive_portal_ttl),
+            ),
+            Some(IpAddr::V6(ipv6)) => self.create_records(
+                request,
+                RData::AAAA(hickory_server::proto::rr::rdata::AAAA(ipv6)),
+                Some(self.captive_portal_ttl),
+            ),
+            None => Vec::new(),
+        };
+
+        let mut authority = vec![];
+        if records.is_empty() && !self.soa_names.is_empty() && self.soa_names.len() >= 2 {
+            let rdata = RData::SOA(SOA::new(
+                Name::from_str_relaxed(&self.soa_names[0]).unwrap(),
+                Name::from_str_relaxed(&self.soa_names[1]).unwrap(),
+                1000,
+                60,
+                60,
+                31356000,
+                60,
+            ));
+            authority.push(Record::from_rdata(
+                request.query().name().into(),
+                60,
+                rdata,
+            ));
+        }
+
+        let builder = MessageResponseBuilder::from_message_request(request);
+        let mut header = Header::response_from_request(request.header());
+        header.set_authoritative(true);
+        let response = builder.build(header, records.iter(), authority.iter(), &[], &[]);
+        Ok(responder.send_response(response).await?)
+    }
+
     async fn do_handle_request_rootzone<R: ResponseHandler>(
         &self,
         request: &Request,
@@ -324,6 +589,14 @@ impl Handler {
                 0,
             ));
             records = vec![Record::from_rdata(request.query().name().into(), 60, rdata)];
+        } else if request.query().query_type() == RecordType::DNSKEY {
+            for key in &self.dnssec_keys {
+                records.push(Record::from_rdata(
+                    request.query().name().into(),
+                    3600,
+                    RData::DNSKEY(key.dnskey_rdata()),
+                ));
+            }
         }
 
         let response = self.build_response(request, records);
@@ -343,7 +616,24 @@ impl Handler {
             return Err(Error::InvalidMessageType(request.message_type()));
         }
 
+        if self.captive_portal
+            && matches!(request.query().query_type(), RecordType::A | RecordType::AAAA)
+            && !self
+                .captive_portal_allowed_suffixes
+                .iter()
+                .any(|suffix| suffix.zone_of(request.query().name()))
+        {
+            return self.do_handle_request_captive_portal(request, response).await;
+        }
+
         match request.query().name() {
+            name if self
+                .dnscrypt_cert_zone
+                .as_ref()
+                .is_some_and(|zone| zone.zone_of(name)) =>
+            {
+                self.do_handle_request_dnscrypt_cert(request, response).await
+            }
             name if self.myip_zone.zone_of(name) => {
                 self.do_handle_request_myip(request, response).await
             }
@@ -376,12 +666,183 @@ impl Handler {
                 self.do_handle_request_timestamp(request, response, true)
                     .await
             }
-            name if self.root_zone.zone_of(name) => {
+            // Only the zone apex itself (not every subdomain of it) gets
+            // the rootzone handling (NS/SOA/DNSKEY) — an unmatched name
+            // under `--domain` (e.g. a typo) needs to fall through to
+            // forwarding/NXDOMAIN below, not be swallowed here as NODATA.
+            name if *name == self.root_zone => {
                 self.do_handle_request_rootzone(request, response).await
             }
 
-            name => Err(Error::InvalidZone(name.clone())),
+            name => {
+                let name = name.clone();
+                self.do_handle_request_nxdomain(request, response, name).await
+            }
+        }
+    }
+
+    /// Answer a name outside every known diagnostic zone by forwarding it
+    /// upstream (if any `--forwarders` are configured) and otherwise with
+    /// NXDOMAIN rather than failing hard; when DNSSEC is enabled and the
+    /// query asked for it, attach a synthesized NSEC + RRSIG so
+    /// denial-of-existence still validates.
+    async fn do_handle_request_nxdomain<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut responder: R,
+        name: LowerName,
+    ) -> Result<ResponseInfo, Error> {
+        if !self.forwarders.is_empty() {
+            match forward::forward(
+                &self.forwarders,
+                &self.forward_cache,
+                &name,
+                request.query().query_type(),
+            )
+            .await
+            {
+                Ok((records, is_nxdomain)) if !is_nxdomain => {
+                    let builder = MessageResponseBuilder::from_message_request(request);
+                    let mut header = Header::response_from_request(request.header());
+                    header.set_authoritative(false);
+                    header.set_recursion_available(true);
+                    let response = builder.build(header, records.iter(), &[], &[], &[]);
+                    return Ok(responder.send_response(response).await?);
+                }
+                Ok(_) => {
+                    // Upstream said NXDOMAIN; fall through to our own
+                    // NXDOMAIN response below.
+                }
+                Err(e) => {
+                    warn!("forwarding {name} failed, falling back to NXDOMAIN: {e}");
+                }
+            }
+        }
+
+        let mut authority = vec![];
+
+        if !self.soa_names.is_empty() && self.soa_names.len() >= 2 {
+            let rdata = RData::SOA(SOA::new(
+                Name::from_str_relaxed(&self.soa_names[0]).unwrap(),
+                Name::from_str_relaxed(&self.soa_names[1]).unwrap(),
+                1000,
+                60,
+                60,
+                31356000,
+                60,
+            ));
+            authority.push(Record::from_rdata(Name::from(name.clone()), 60, rdata));
+        }
+
+        if self.dnssec_do_requested(request) {
+            let nsec = dnssec::synthesize_nsec(&Name::from(name));
+            authority.push(nsec.clone());
+            for key in &self.dnssec_keys {
+                authority.push(dnssec::sign_rrset(
+                    key,
+                    &self.domain_name,
+                    RecordType::NSEC,
+                    &[nsec.clone()],
+                    self.dnssec_validity_secs,
+                ));
+            }
+        }
+
+        let builder = MessageResponseBuilder::from_message_request(request);
+        let mut header = Header::response_from_request(request.header());
+        header.set_authoritative(true);
+        header.set_response_code(ResponseCode::NXDomain);
+        let response = builder.build(header, [].iter(), authority.iter(), &[], &[]);
+        Ok(responder.send_response(response).await?)
+    }
+}
+
+/// Captures the bytes a `ResponseHandler` would otherwise write to a socket,
+/// so that transports which don't go through `ServerFuture` (DNSCrypt and
+/// mDNS) can still dispatch through the normal `Handler` logic.
+#[derive(Clone)]
+struct CapturingResponseHandle {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+#[async_trait::async_trait]
+impl ResponseHandler for CapturingResponseHandle {
+    async fn send_response<'a>(
+        &mut self,
+        response: hickory_server::authority::MessageResponse<
+            'a,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut bytes = Vec::with_capacity(512);
+        let info = {
+            let mut encoder = BinEncoder::new(&mut bytes);
+            response
+                .destructure()
+                .0
+                .emit(&mut encoder)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        };
+        *self.bytes.lock().unwrap() = bytes;
+        Ok(info)
+    }
+}
+
+impl Handler {
+    /// Decode a plaintext DNS message (already decrypted by a transport such
+    /// as DNSCrypt) and run it through the same dispatch as every other
+    /// listener, returning the wire-format response bytes to send back.
+    ///
+    /// `suppress_authoritative` clears the AA bit on the encoded response
+    /// afterwards; every `do_handle_request_*` path sets it unconditionally,
+    /// but protocols like mDNS, where AA means something different (or
+    /// nothing), need it cleared rather than threading a flag through every
+    /// response builder.
+    pub async fn handle_wire_message(
+        &self,
+        bytes: &[u8],
+        src: SocketAddr,
+        protocol: Protocol,
+        suppress_authoritative: bool,
+    ) -> Option<Vec<u8>> {
+        let mut decoder = BinDecoder::new(bytes);
+        let message = match MessageRequest::read(&mut decoder) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("failed to decode {protocol} query from {src}: {e}");
+                return None;
+            }
+        };
+        let request = Request::new(message, src, protocol);
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let responder = CapturingResponseHandle {
+            bytes: captured.clone(),
+        };
+        self.handle_request(&request, responder).await;
+        let mut bytes = captured.lock().unwrap().clone();
+        if bytes.is_empty() {
+            return None;
         }
+        if suppress_authoritative && bytes.len() > 2 {
+            bytes[2] &= !0x04;
+        }
+        Some(bytes)
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn metrics_proto(protocol: Protocol) -> Proto {
+    match protocol {
+        Protocol::Udp => Proto::Udp,
+        Protocol::Tcp => Proto::Tcp,
+        Protocol::Tls => Proto::Tls,
+        Protocol::Https => Proto::Doh,
+        Protocol::Quic => Proto::Quic,
+        _ => Proto::Tcp,
     }
 }
 
@@ -392,8 +853,18 @@ impl RequestHandler for Handler {
         request: &Request,
         response: R,
     ) -> ResponseInfo {
+        #[cfg(feature = "metrics")]
+        let (_timer, _inflight) = match &self.metrics {
+            Some(m) => {
+                let proto = metrics_proto(request.protocol());
+                m.record_query(proto);
+                (Some(m.start_timer(proto)), Some(m.inflight_guard(proto)))
+            }
+            None => (None, None),
+        };
+
         // try to handle request
-        match self.do_handle_request(request, response).await {
+        let info = match self.do_handle_request(request, response).await {
             Ok(info) => info,
             Err(error) => {
                 error!("Error in RequestHandler: {error}");
@@ -401,6 +872,14 @@ impl RequestHandler for Handler {
                 header.set_response_code(ResponseCode::ServFail);
                 header.into()
             }
+        };
+
+        #[cfg(feature = "metrics")]
+        if let Some(m) = &self.metrics {
+            let proto = metrics_proto(request.protocol());
+            m.record_response(proto, &info.response_code().to_string());
         }
+
+        info
     }
 }
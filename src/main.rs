@@ -1,5 +1,5 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{CommandFactory, FromArgMatches};
 use daemonize::Daemonize;
 use handler::{Handler};
 use options::Options;
@@ -8,8 +8,16 @@ use std::time::Duration;
 use tokio::net::{TcpListener, UdpSocket};
 use trust_dns_server::ServerFuture;
 
+mod dnscrypt;
+mod dnssec;
+mod forward;
 mod handler;
+mod mdns;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod options;
+mod relay;
+mod tls_reload;
 
 #[link(name = "c")]
 extern "C" {
@@ -52,9 +60,103 @@ pub fn read_key(
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let options = Options::parse();
-    let handler = Handler::from_options(&options);
-    let mut server = ServerFuture::new(handler);
+
+    let matches = Options::command().get_matches();
+    let mut options = Options::from_arg_matches(&matches)?;
+    if let Some(config_path) = options.config.clone() {
+        let raw = std::fs::read_to_string(&config_path)
+            .with_context(|| format!("reading --config file {config_path}"))?;
+        let file_config: options::ConfigFile = toml::from_str(&raw)
+            .with_context(|| format!("parsing --config file {config_path} as TOML"))?;
+        options.apply_config_file(file_config, &matches);
+    }
+    options
+        .validate()
+        .map_err(|e| anyhow::anyhow!("invalid configuration: {e}"))?;
+
+    let mut handler = Handler::from_options(&options);
+
+    let dnscrypt_certs = if !options.dnscrypt.is_empty() || !options.dnscrypt_tcp.is_empty() {
+        let provider_name = options
+            .dnscrypt_provider_name
+            .clone()
+            .expect("--dnscrypt-provider-name is required when --dnscrypt/--dnscrypt-tcp is set");
+        let provider = match std::fs::read(&options.dnscrypt_provider_keyfile) {
+            Ok(bytes) if bytes.len() == 32 => {
+                dnscrypt::ProviderKeyPair::from_bytes(bytes.as_slice().try_into().unwrap())
+            }
+            _ => {
+                tracing::warn!(
+                    "no usable DNSCrypt provider key at {}, generating an ephemeral one",
+                    options.dnscrypt_provider_keyfile
+                );
+                dnscrypt::ProviderKeyPair::generate()
+            }
+        };
+        let certs = std::sync::Arc::new(dnscrypt::CertManager::new(
+            provider,
+            dnscrypt::EsVersion::XChaCha20Poly1305,
+        ));
+        dnscrypt::spawn_cert_rotation(certs.clone());
+        handler = handler.with_dnscrypt(&provider_name, certs.clone());
+        Some(certs)
+    } else {
+        None
+    };
+
+    if !options.dnssec_zsk.is_empty() {
+        let keys: Vec<std::sync::Arc<dnssec::ZoneSigningKey>> = options
+            .dnssec_zsk
+            .iter()
+            .map(|spec| {
+                dnssec::ZoneSigningKey::load(spec)
+                    .map(std::sync::Arc::new)
+                    .with_context(|| format!("loading --dnssec-zsk {spec}"))
+            })
+            .collect::<Result<_>>()?;
+        handler = handler.with_dnssec(keys);
+    }
+
+    if !options.forwarders.is_empty() {
+        handler = handler.with_forwarding(
+            options.forwarders.clone(),
+            options.forward_cache_size,
+            options.forward_negative_ttl,
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_addr) = options.metrics_addr {
+        let metrics = std::sync::Arc::new(metrics::Metrics::new());
+        handler = handler.with_metrics(metrics.clone());
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics_addr, metrics).await {
+                tracing::error!("metrics HTTP server exited: {e}");
+            }
+        });
+    }
+
+    let tls_resolver = if !options.doh.is_empty()
+        || !options.doh6.is_empty()
+        || !options.tls.is_empty()
+        || !options.tls6.is_empty()
+        || !options.quic.is_empty()
+        || !options.quic6.is_empty()
+    {
+        let resolver = tls_reload::ReloadableCertResolver::load(
+            std::path::PathBuf::from(&options.certfile),
+            std::path::PathBuf::from(&options.keyfile),
+        )?;
+        tls_reload::spawn_reload_timer(
+            resolver.clone(),
+            Duration::from_secs(options.tls_reload_interval_secs),
+        );
+        Some(resolver)
+    } else {
+        None
+    };
+
+    let mut server = ServerFuture::new(handler.clone());
 
     for udp in &options.udp {
         server.register_socket(UdpSocket::bind(udp).await?);
@@ -79,75 +181,136 @@ async fn main() -> Result<()> {
     }
 
     for doh in &options.doh {
-        let _ = server.register_https_listener(
+        let resolver = tls_resolver.as_ref().expect("tls_resolver set above");
+        let _ = server.register_https_listener_with_tls_config(
             TcpListener::bind(doh).await?,
             Duration::from_secs(options.tcptimeout),
-            (
-                read_cert(std::path::Path::new(&options.certfile.clone())).unwrap(),
-                read_key(std::path::Path::new(&options.keyfile.clone())).unwrap(),
-            ),
+            resolver.server_config(vec![b"h2".to_vec()]),
             options.domain.clone(),
         );
     }
 
     for doh6 in &options.doh6 {
-        let _ = server.register_https_listener(
+        let resolver = tls_resolver.as_ref().expect("tls_resolver set above");
+        let _ = server.register_https_listener_with_tls_config(
             TcpListener::bind(doh6).await?,
             Duration::from_secs(options.tcptimeout),
-            (
-                read_cert(std::path::Path::new(&options.certfile.clone())).unwrap(),
-                read_key(std::path::Path::new(&options.keyfile.clone())).unwrap(),
-            ),
+            resolver.server_config(vec![b"h2".to_vec()]),
             options.domain.clone(),
         );
     }
 
     for tls in &options.tls {
-        let _ = server.register_tls_listener(
+        let resolver = tls_resolver.as_ref().expect("tls_resolver set above");
+        let _ = server.register_tls_listener_with_tls_config(
             TcpListener::bind(tls).await?,
             Duration::from_secs(options.tcptimeout),
-            (
-                read_cert(std::path::Path::new(&options.certfile.clone())).unwrap(),
-                read_key(std::path::Path::new(&options.keyfile.clone())).unwrap(),
-            ),
+            resolver.server_config(vec![]),
         );
     }
 
     for tls6 in &options.tls6 {
-        let _ = server.register_tls_listener(
+        let resolver = tls_resolver.as_ref().expect("tls_resolver set above");
+        let _ = server.register_tls_listener_with_tls_config(
             TcpListener::bind(tls6).await?,
             Duration::from_secs(options.tcptimeout),
-            (
-                read_cert(std::path::Path::new(&options.certfile.clone())).unwrap(),
-                read_key(std::path::Path::new(&options.keyfile.clone())).unwrap(),
-            ),
+            resolver.server_config(vec![]),
         );
     }
 
     for quic in &options.quic {
-        let _ = server.register_quic_listener(
+        let resolver = tls_resolver.as_ref().expect("tls_resolver set above");
+        let _ = server.register_quic_listener_with_tls_config(
             UdpSocket::bind(quic).await?,
             Duration::from_secs(options.tcptimeout),
-            (
-                read_cert(std::path::Path::new(&options.certfile.clone())).unwrap(),
-                read_key(std::path::Path::new(&options.keyfile.clone())).unwrap(),
-            ),
+            resolver.server_config(vec![b"doq".to_vec()]),
             options.domain.clone(),
         );
     }
 
+    for dnscrypt_udp in &options.dnscrypt {
+        let socket = UdpSocket::bind(dnscrypt_udp).await?;
+        let handler = handler.clone();
+        let certs = dnscrypt_certs.clone().expect("dnscrypt_certs set above");
+        tokio::spawn(async move {
+            if let Err(e) = dnscrypt::run_udp(socket, handler, certs).await {
+                tracing::error!("DNSCrypt UDP listener exited: {e}");
+            }
+        });
+    }
+
+    for dnscrypt_tcp in &options.dnscrypt_tcp {
+        let listener = TcpListener::bind(dnscrypt_tcp).await?;
+        let handler = handler.clone();
+        let certs = dnscrypt_certs.clone().expect("dnscrypt_certs set above");
+        tokio::spawn(async move {
+            if let Err(e) = dnscrypt::run_tcp(listener, handler, certs).await {
+                tracing::error!("DNSCrypt TCP listener exited: {e}");
+            }
+        });
+    }
+
+    if !options.relay.is_empty() || !options.relay_tcp.is_empty() {
+        if options.relay_allowed_upstreams.is_empty() {
+            anyhow::bail!(
+                "--relay-allowed-upstreams must list at least one upstream when --relay/--relay-tcp is set"
+            );
+        }
+        let relay_config = std::sync::Arc::new(relay::RelayConfig {
+            allowed_upstreams: options.relay_allowed_upstreams.clone(),
+            rate_limiter: std::sync::Arc::new(relay::RateLimiter::new(
+                options.relay_rate_limit_per_second,
+            )),
+        });
+
+        for relay_udp in &options.relay {
+            let socket = UdpSocket::bind(relay_udp).await?;
+            let relay_config = relay_config.clone();
+            tokio::spawn(async move {
+                if let Err(e) = relay::run_udp(socket, relay_config).await {
+                    tracing::error!("relay UDP listener exited: {e}");
+                }
+            });
+        }
+
+        for relay_tcp in &options.relay_tcp {
+            let listener = TcpListener::bind(relay_tcp).await?;
+            let relay_config = relay_config.clone();
+            tokio::spawn(async move {
+                if let Err(e) = relay::run_tcp(listener, relay_config).await {
+                    tracing::error!("relay TCP listener exited: {e}");
+                }
+            });
+        }
+    }
+
     for quic6 in &options.quic6 {
-        let _ = server.register_quic_listener(
+        let resolver = tls_resolver.as_ref().expect("tls_resolver set above");
+        let _ = server.register_quic_listener_with_tls_config(
             UdpSocket::bind(quic6).await?,
             Duration::from_secs(options.tcptimeout),
-            (
-                read_cert(std::path::Path::new(&options.certfile.clone())).unwrap(),
-                read_key(std::path::Path::new(&options.keyfile.clone())).unwrap(),
-            ),
+            resolver.server_config(vec![b"doq".to_vec()]),
             options.domain.clone(),
         );
     }
     
+    if options.mdns {
+        let v4_handler = handler.clone();
+        let mdns_interface = options.mdns_interface;
+        tokio::spawn(async move {
+            if let Err(e) = mdns::run_v4(v4_handler, mdns_interface).await {
+                tracing::error!("mDNS IPv4 listener exited: {e}");
+            }
+        });
+
+        let v6_handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mdns::run_v6(v6_handler).await {
+                tracing::error!("mDNS IPv6 listener exited: {e}");
+            }
+        });
+    }
+
     // Drop privileges if I'm run as root.
     let mut running_as_root = false;
     unsafe {
@@ -0,0 +1,150 @@
+//! Optional Multicast DNS (RFC 6762) responder for the diagnostic zones.
+//!
+//! This binds a UDP socket joined to the mDNS multicast groups
+//! (224.0.0.251 for IPv4, ff02::fb for IPv6) on port 5353 and feeds
+//! received queries through the same `Handler::handle_wire_message` path
+//! DNSCrypt uses, so `myip`, `random`, `timestamp`, etc. all answer on the
+//! local link too. Only the handful of mDNS wire-format differences from
+//! unicast DNS that matter for a simple responder are implemented: the QU
+//! (unicast-response) bit on the question, the cache-flush bit on answers,
+//! and a cleared AA bit on responses.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::net::UdpSocket;
+use tracing::*;
+
+use crate::handler::Handler;
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_V4_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_V6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const MAX_PACKET: usize = 4096;
+
+/// Walk a (possibly compressed) name starting at `offset`, returning the
+/// offset of the first byte after it.
+fn skip_name(bytes: &[u8], offset: usize) -> Option<usize> {
+    let mut i = offset;
+    loop {
+        let len = *bytes.get(i)?;
+        if len == 0 {
+            return Some(i + 1);
+        } else if len & 0xc0 == 0xc0 {
+            // Compression pointer: two bytes, regardless of what it points at.
+            return Some(i + 2);
+        } else {
+            i += 1 + len as usize;
+        }
+    }
+}
+
+/// Whether the question's QU bit (the top bit of the QCLASS field) is set,
+/// meaning the querier asked for a unicast reply instead of a multicast one.
+fn unicast_response_requested(bytes: &[u8]) -> bool {
+    if bytes.len() < 12 {
+        return false;
+    }
+    let qdcount = u16::from_be_bytes([bytes[4], bytes[5]]);
+    if qdcount == 0 {
+        return false;
+    }
+    let Some(after_name) = skip_name(bytes, 12) else {
+        return false;
+    };
+    let class_offset = after_name + 2; // skip QTYPE
+    match bytes.get(class_offset) {
+        Some(class_hi) => class_hi & 0x80 != 0,
+        None => false,
+    }
+}
+
+/// Set the cache-flush bit (RFC 6762 section 10.2) on the CLASS field of
+/// every answer record in an encoded response, in place.
+fn set_cache_flush_bits(bytes: &mut [u8]) {
+    if bytes.len() < 12 {
+        return;
+    }
+    let qdcount = u16::from_be_bytes([bytes[4], bytes[5]]);
+    let ancount = u16::from_be_bytes([bytes[6], bytes[7]]);
+
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        offset = match skip_name(bytes, offset) {
+            Some(o) => o + 4, // QTYPE + QCLASS
+            None => return,
+        };
+    }
+
+    for _ in 0..ancount {
+        let name_end = match skip_name(bytes, offset) {
+            Some(o) => o,
+            None => return,
+        };
+        let class_offset = name_end + 2; // skip TYPE
+        if bytes.len() < class_offset + 2 {
+            return;
+        }
+        bytes[class_offset] |= 0x80;
+
+        let rdlength_offset = class_offset + 2 + 4; // skip CLASS + TTL
+        if bytes.len() < rdlength_offset + 2 {
+            return;
+        }
+        let rdlength = u16::from_be_bytes([bytes[rdlength_offset], bytes[rdlength_offset + 1]]);
+        offset = rdlength_offset + 2 + rdlength as usize;
+    }
+}
+
+async fn process_packet(
+    socket: &UdpSocket,
+    handler: &Handler,
+    bytes: &[u8],
+    src: SocketAddr,
+    group: SocketAddr,
+) {
+    let unicast = unicast_response_requested(bytes);
+
+    // mDNS responses are never "authoritative" in the unicast-DNS sense;
+    // suppress the AA bit every `do_handle_request_*` path sets.
+    let Some(mut response) = handler
+        .handle_wire_message(bytes, src, hickory_server::server::Protocol::Udp, true)
+        .await
+    else {
+        return;
+    };
+
+    set_cache_flush_bits(&mut response);
+
+    let dest = if unicast { src } else { group };
+    if let Err(e) = socket.send_to(&response, dest).await {
+        warn!("mDNS: failed to send response to {dest}: {e}");
+    }
+}
+
+/// Join the IPv4 mDNS group on `interface` and answer queries until the
+/// socket errors out.
+pub async fn run_v4(handler: Handler, interface: Ipv4Addr) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).await?;
+    socket.join_multicast_v4(MDNS_V4_GROUP, interface)?;
+    let group = SocketAddr::new(MDNS_V4_GROUP.into(), MDNS_PORT);
+
+    let mut buf = [0u8; MAX_PACKET];
+    loop {
+        let (len, src) = socket.recv_from(&mut buf).await?;
+        process_packet(&socket, &handler, &buf[..len], src, group).await;
+    }
+}
+
+/// Join the IPv6 mDNS group on the default interface and answer queries
+/// until the socket errors out.
+pub async fn run_v6(handler: Handler) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, MDNS_PORT)).await?;
+    socket.join_multicast_v6(&MDNS_V6_GROUP, 0)?;
+    let group = SocketAddr::new(MDNS_V6_GROUP.into(), MDNS_PORT);
+
+    let mut buf = [0u8; MAX_PACKET];
+    loop {
+        let (len, src) = socket.recv_from(&mut buf).await?;
+        process_packet(&socket, &handler, &buf[..len], src, group).await;
+    }
+}
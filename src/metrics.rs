@@ -0,0 +1,184 @@
+//! Optional Prometheus metrics subsystem (feature-gated behind `metrics`).
+//!
+//! `Registry` is shared into `Handler` so every request path can record
+//! against it, and `serve` exposes it over a small HTTP endpoint in the
+//! standard Prometheus text exposition format.
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Instant};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request as HttpRequest, Response as HttpResponse, Server,
+};
+use prometheus::{Encoder, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use tracing::*;
+
+/// The protocol a query arrived over, used as a label value.
+#[derive(Clone, Copy, Debug)]
+pub enum Proto {
+    Udp,
+    Tcp,
+    Doh,
+    Tls,
+    Quic,
+}
+
+impl Proto {
+    fn as_str(self) -> &'static str {
+        match self {
+            Proto::Udp => "udp",
+            Proto::Tcp => "tcp",
+            Proto::Doh => "doh",
+            Proto::Tls => "tls",
+            Proto::Quic => "quic",
+        }
+    }
+}
+
+/// All the series `Handler` instruments, bundled behind one shared registry.
+#[derive(Clone)]
+pub struct Metrics {
+    // `prometheus` metric types don't implement `Debug`; `Handler` derives
+    // it, so we hand-roll a placeholder impl below instead.
+    pub registry: Registry,
+    queries_total: IntCounterVec,
+    responses_total: IntCounterVec,
+    query_latency: HistogramVec,
+    inflight: IntGaugeVec,
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let queries_total = IntCounterVec::new(
+            Opts::new("dnssrc_queries_total", "Total queries received, by protocol"),
+            &["protocol"],
+        )
+        .unwrap();
+        let responses_total = IntCounterVec::new(
+            Opts::new(
+                "dnssrc_responses_total",
+                "Total responses sent, by protocol and response code",
+            ),
+            &["protocol", "rcode"],
+        )
+        .unwrap();
+        let query_latency = HistogramVec::new(
+            prometheus::HistogramOpts::new(
+                "dnssrc_query_duration_seconds",
+                "Time spent processing a query end to end",
+            ),
+            &["protocol"],
+        )
+        .unwrap();
+        let inflight = IntGaugeVec::new(
+            Opts::new(
+                "dnssrc_inflight_connections",
+                "Connections currently being served, by protocol",
+            ),
+            &["protocol"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(queries_total.clone())).unwrap();
+        registry
+            .register(Box::new(responses_total.clone()))
+            .unwrap();
+        registry.register(Box::new(query_latency.clone())).unwrap();
+        registry.register(Box::new(inflight.clone())).unwrap();
+
+        Metrics {
+            registry,
+            queries_total,
+            responses_total,
+            query_latency,
+            inflight,
+        }
+    }
+
+    pub fn record_query(&self, protocol: Proto) {
+        self.queries_total.with_label_values(&[protocol.as_str()]).inc();
+    }
+
+    pub fn record_response(&self, protocol: Proto, rcode: &str) {
+        self.responses_total
+            .with_label_values(&[protocol.as_str(), rcode])
+            .inc();
+    }
+
+    pub fn inflight_guard(&self, protocol: Proto) -> InflightGuard {
+        self.inflight.with_label_values(&[protocol.as_str()]).inc();
+        InflightGuard {
+            gauge: self.inflight.clone(),
+            protocol,
+        }
+    }
+
+    /// Time a query, recording the elapsed seconds into the latency
+    /// histogram once the returned timer is dropped.
+    pub fn start_timer(&self, protocol: Proto) -> QueryTimer {
+        QueryTimer {
+            histogram: self.query_latency.clone(),
+            protocol,
+            start: Instant::now(),
+        }
+    }
+}
+
+pub struct InflightGuard {
+    gauge: IntGaugeVec,
+    protocol: Proto,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.gauge.with_label_values(&[self.protocol.as_str()]).dec();
+    }
+}
+
+pub struct QueryTimer {
+    histogram: HistogramVec,
+    protocol: Proto,
+    start: Instant,
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        self.histogram
+            .with_label_values(&[self.protocol.as_str()])
+            .observe(elapsed);
+    }
+}
+
+async fn render(registry: Registry, _req: HttpRequest<Body>) -> Result<HttpResponse<Body>, Infallible> {
+    let encoder = TextEncoder::new();
+    let metric_families = registry.gather();
+    let mut buffer = Vec::new();
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+    Ok(HttpResponse::builder()
+        .header("content-type", encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap())
+}
+
+/// Serve the Prometheus text exposition format on `addr` until the process
+/// exits. Every request path (`/metrics` included) gets the same output.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), hyper::Error> {
+    let make_svc = make_service_fn(move |_conn| {
+        let registry = metrics.registry.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| render(registry.clone(), req)))
+        }
+    });
+
+    info!("serving Prometheus metrics on {addr}");
+    Server::bind(&addr).serve(make_svc).await
+}
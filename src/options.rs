@@ -1,22 +1,32 @@
-use clap::{ArgGroup, Parser};
+use clap::{ArgGroup, ArgMatches, Parser, ValueSource};
+use serde::Deserialize;
 use std::net::SocketAddr;
 
 #[derive(Parser, Clone, Debug)]
 #[clap(author = "David Groves", version, about = "A DNS server for testing")]
 #[command(group(
     ArgGroup::new("socket")
-        .args(["udp", "udp6", "tcp", "tcp6", "doh", "doh6", "tls", "tls6", "quic", "quic6"])
+        .args([
+            "udp", "udp6", "tcp", "tcp6", "doh", "doh6", "tls", "tls6", "quic", "quic6",
+            "dnscrypt", "dnscrypt_tcp",
+        ])
         .multiple(true)
-        .required(true)
 ))]
 #[command(group(
     ArgGroup::new("records")
         .args(["ns_records", "soa_names"])
         .multiple(true)
-        .required(true)
 ))]
 
 pub struct Options {
+    // Path to a TOML file mirroring a subset of this struct's fields (see
+    // `ConfigFile`), used to fill in
+    // anything not given on the command line or via environment variables.
+    // Handy for checking in reproducible server configurations instead of
+    // juggling a long flag list. Explicit CLI/env values always win.
+    #[clap(long, env = "DNSSRC_CONFIG")]
+    pub config: Option<String>,
+
     // UDP socket to listen on.
     #[clap(long, env = "DNSSRC_UDP_ADDR")]
     #[arg(group = "socket")]
@@ -68,7 +78,7 @@ pub struct Options {
     pub quic6: Vec<SocketAddr>,
 
     // Domain name.
-    #[clap(long, env = "DNSSRC_DOMAIN")]
+    #[clap(long, env = "DNSSRC_DOMAIN", default_value = "")]
     pub domain: String,
 
     // Domain name.
@@ -108,4 +118,329 @@ pub struct Options {
     #[clap(long, env = "SOA_NAMES")]
     #[arg(num_args(0..))]
     pub soa_names: Vec<String>,
+
+    // UDP socket to listen on for DNSCrypt v2.
+    #[clap(long, env = "DNSSRC_DNSCRYPT_ADDR")]
+    #[arg(group = "socket")]
+    pub dnscrypt: Vec<SocketAddr>,
+
+    // TCP socket to listen on for DNSCrypt v2.
+    #[clap(long, env = "DNSSRC_DNSCRYPT_TCP_ADDR")]
+    #[arg(group = "socket")]
+    pub dnscrypt_tcp: Vec<SocketAddr>,
+
+    // DNSCrypt provider name, e.g. "2.dnscrypt-cert.example.com". Required
+    // when any --dnscrypt/--dnscrypt-tcp listener is configured.
+    #[clap(long, env = "DNSSRC_DNSCRYPT_PROVIDER_NAME")]
+    pub dnscrypt_provider_name: Option<String>,
+
+    // Path to the DNSCrypt long-term provider Ed25519 secret key (32 raw
+    // bytes). If it doesn't exist, a fresh key pair is generated and kept
+    // in memory for the life of the process.
+    #[clap(
+        long,
+        env = "DNSSRC_DNSCRYPT_PROVIDER_KEYFILE",
+        default_value = "dnscrypt/provider.key"
+    )]
+    pub dnscrypt_provider_keyfile: String,
+
+    // HTTP address to expose Prometheus metrics on. Only available when
+    // built with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    #[clap(long, env = "DNSSRC_METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    // Instead of always answering with a fixed `ttl`, count each served
+    // name's TTL down from `ttl` towards `ttl_jitter_floor`, perturbed by
+    // `ttl_jitter_percent`, so resolver cache countdown/refresh behavior can
+    // be exercised.
+    #[clap(long, env = "DNSSRC_TTL_JITTER")]
+    pub ttl_jitter: bool,
+
+    // Once the countdown remaining TTL drops below this many seconds,
+    // jitter is applied on top of it.
+    #[clap(long, default_value = "10", env = "DNSSRC_TTL_JITTER_LOW_WATER")]
+    pub ttl_jitter_low_water: u32,
+
+    // The TTL never counts down below this floor.
+    #[clap(long, default_value = "0", env = "DNSSRC_TTL_JITTER_FLOOR")]
+    pub ttl_jitter_floor: u32,
+
+    // Uniform jitter applied to the countdown TTL, as +/- this percent.
+    #[clap(long, default_value = "10", env = "DNSSRC_TTL_JITTER_PERCENT")]
+    pub ttl_jitter_percent: u32,
+
+    // How often to re-read certfile/keyfile from disk, so a renewed
+    // certificate gets picked up by the DoH/TLS/QUIC listeners without a
+    // restart.
+    #[clap(long, default_value = "60", env = "DNSSRC_TLS_RELOAD_INTERVAL")]
+    pub tls_reload_interval_secs: u64,
+
+    // UDP socket to listen on for the Anonymized DNSCrypt relay.
+    #[clap(long, env = "DNSSRC_RELAY_ADDR")]
+    pub relay: Vec<SocketAddr>,
+
+    // TCP socket to listen on for the Anonymized DNSCrypt relay.
+    #[clap(long, env = "DNSSRC_RELAY_TCP_ADDR")]
+    pub relay_tcp: Vec<SocketAddr>,
+
+    // Upstream resolver addresses the relay is permitted to forward to.
+    // Required when --relay/--relay-tcp is set: the relay refuses to
+    // forward anywhere else.
+    #[clap(long, env = "DNSSRC_RELAY_ALLOWED_UPSTREAMS")]
+    #[arg(num_args(0..))]
+    pub relay_allowed_upstreams: Vec<std::net::IpAddr>,
+
+    // Maximum relay packets accepted per client IP per second.
+    #[clap(long, default_value = "50", env = "DNSSRC_RELAY_RATE_LIMIT")]
+    pub relay_rate_limit_per_second: u32,
+
+    // Zone signing keys to sign DO-bit answers with, each given as
+    // "<algorithm>:<path>" where algorithm is "ecdsap256sha256" or
+    // "ed25519" and path is a PEM or DER private key. May be repeated to
+    // sign with more than one key.
+    #[clap(long, env = "DNSSRC_DNSSEC_ZSK")]
+    #[arg(num_args(0..))]
+    pub dnssec_zsk: Vec<String>,
+
+    // How long a freshly minted RRSIG stays valid for.
+    #[clap(
+        long,
+        default_value = "864000",
+        env = "DNSSRC_DNSSEC_SIGNATURE_VALIDITY"
+    )]
+    pub dnssec_signature_validity_secs: u32,
+
+    // Set the AD (authentic data) bit on signed responses.
+    #[clap(long, env = "DNSSRC_DNSSEC_SET_AD")]
+    pub dnssec_set_ad: bool,
+
+    // Upstream resolvers to forward to when a query falls outside every
+    // known diagnostic zone, instead of answering NXDOMAIN. Tried in order;
+    // the first to answer wins. May be repeated.
+    #[clap(long, env = "DNSSRC_FORWARDERS")]
+    #[arg(num_args(0..))]
+    pub forwarders: Vec<std::net::IpAddr>,
+
+    // Maximum number of forwarded answers to keep cached at once, evicted
+    // least-recently-used once full.
+    #[clap(long, default_value = "10000", env = "DNSSRC_FORWARD_CACHE_SIZE")]
+    pub forward_cache_size: usize,
+
+    // TTL to cache a forwarded NXDOMAIN/NODATA answer for.
+    #[clap(long, default_value = "60", env = "DNSSRC_FORWARD_NEGATIVE_TTL")]
+    pub forward_negative_ttl: u32,
+
+    // Answer every A/AAAA query, regardless of QNAME, with
+    // --captive-portal-address, as a constrained gateway's captive-portal
+    // DNS would. Names under --captive-portal-allowed-suffixes keep getting
+    // the normal diagnostic-zone/NXDOMAIN behavior.
+    #[clap(long, env = "DNSSRC_CAPTIVE_PORTAL")]
+    pub captive_portal: bool,
+
+    // The address handed out for every wildcard-matched A or AAAA query.
+    // Only the matching family (v4 for A, v6 for AAAA) gets a real answer;
+    // the other family gets NODATA+SOA.
+    #[clap(long, env = "DNSSRC_CAPTIVE_PORTAL_ADDRESS")]
+    pub captive_portal_address: Option<std::net::IpAddr>,
+
+    // TTL for captive-portal wildcard answers.
+    #[clap(long, default_value = "60", env = "DNSSRC_CAPTIVE_PORTAL_TTL")]
+    pub captive_portal_ttl: u32,
+
+    // Name suffixes excluded from wildcard captive-portal answers (e.g.
+    // connectivity-check domains), falling through to normal handling.
+    #[clap(long, env = "DNSSRC_CAPTIVE_PORTAL_ALLOWED_SUFFIXES")]
+    #[arg(num_args(0..))]
+    pub captive_portal_allowed_suffixes: Vec<String>,
+
+    // Answer mDNS (RFC 6762) queries on the local link in addition to the
+    // configured unicast listeners, joining 224.0.0.251:5353 and
+    // ff02::fb:5353.
+    #[clap(long, env = "DNSSRC_MDNS")]
+    pub mdns: bool,
+
+    // IPv4 interface address to join the mDNS multicast group on.
+    #[clap(long, default_value = "0.0.0.0", env = "DNSSRC_MDNS_INTERFACE")]
+    pub mdns_interface: std::net::Ipv4Addr,
+}
+
+impl Options {
+    /// Fill in anything left at its clap default from `file`, which was
+    /// loaded from the path given by `--config`. Anything set explicitly on
+    /// the command line or via an environment variable is left untouched.
+    pub fn apply_config_file(&mut self, file: ConfigFile, matches: &ArgMatches) {
+        macro_rules! fill_list {
+            ($field:ident) => {
+                // These fields have no clap `default_value`, so when the
+                // flag/env var is absent `value_source()` is `None`, not
+                // `Some(ValueSource::DefaultValue)` — both mean "nothing was
+                // set on the command line or via the environment" and should
+                // be fillable from `--config`.
+                if matches!(
+                    matches.value_source(stringify!($field)),
+                    None | Some(ValueSource::DefaultValue)
+                ) && !file.$field.is_empty()
+                {
+                    self.$field = file.$field;
+                }
+            };
+        }
+        macro_rules! fill_opt {
+            ($field:ident) => {
+                if matches.value_source(stringify!($field)) == Some(ValueSource::DefaultValue) {
+                    if let Some(value) = file.$field {
+                        self.$field = value;
+                    }
+                }
+            };
+        }
+
+        fill_list!(udp);
+        fill_list!(udp6);
+        fill_list!(tcp);
+        fill_list!(tcp6);
+        fill_list!(doh);
+        fill_list!(doh6);
+        fill_list!(tls);
+        fill_list!(tls6);
+        fill_list!(quic);
+        fill_list!(quic6);
+        fill_list!(ns_records);
+        fill_list!(soa_names);
+        fill_opt!(domain);
+        fill_opt!(ttl);
+        fill_opt!(user);
+        fill_opt!(group);
+        fill_opt!(certfile);
+        fill_opt!(keyfile);
+    }
+
+    /// The `socket` and `records` arg groups used to be enforced by clap
+    /// directly, but `--config` can now supply them too, so the
+    /// "at least one of" check has to happen after the config file (if any)
+    /// has been merged in.
+    pub fn validate(&self) -> Result<(), String> {
+        // Listeners that serve the diagnostic zones out of `Handler`, and so
+        // need a `--domain` and `--ns-records`/`--soa-names` to answer for.
+        // The relay (src/relay.rs) never touches `Handler`/`root_zone` at
+        // all, so it's deliberately excluded from this set.
+        let any_zone_socket = !self.udp.is_empty()
+            || !self.udp6.is_empty()
+            || !self.tcp.is_empty()
+            || !self.tcp6.is_empty()
+            || !self.doh.is_empty()
+            || !self.doh6.is_empty()
+            || !self.tls.is_empty()
+            || !self.tls6.is_empty()
+            || !self.quic.is_empty()
+            || !self.quic6.is_empty()
+            || !self.dnscrypt.is_empty()
+            || !self.dnscrypt_tcp.is_empty()
+            || self.mdns;
+        let relay_configured = !self.relay.is_empty() || !self.relay_tcp.is_empty();
+
+        if !any_zone_socket && !relay_configured {
+            return Err("at least one socket option (--udp, --tcp, --doh, ...) is required, on the command line, via the environment, or in --config".to_string());
+        }
+
+        // A relay-only setup (e.g. using dnssrc purely as the Anonymized
+        // DNSCrypt relay hop) never serves the diagnostic zones, so it
+        // shouldn't need a --domain or --ns-records/--soa-names just to
+        // satisfy this check.
+        if any_zone_socket {
+            if self.ns_records.is_empty() && self.soa_names.is_empty() {
+                return Err("at least one of --ns-records or --soa-names is required, on the command line, via the environment, or in --config".to_string());
+            }
+
+            if self.domain.is_empty() {
+                return Err("--domain is required, on the command line, via the environment, or in --config".to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Mirrors the subset of `Options` that makes sense to check in as a
+/// reproducible file: socket lists, zone identity, and cert/key paths.
+/// Anything newer and more situational (DNSCrypt, metrics, TTL jitter) stays
+/// CLI/env-only for now.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub udp: Vec<SocketAddr>,
+    #[serde(default)]
+    pub udp6: Vec<SocketAddr>,
+    #[serde(default)]
+    pub tcp: Vec<SocketAddr>,
+    #[serde(default)]
+    pub tcp6: Vec<SocketAddr>,
+    #[serde(default)]
+    pub doh: Vec<SocketAddr>,
+    #[serde(default)]
+    pub doh6: Vec<SocketAddr>,
+    #[serde(default)]
+    pub tls: Vec<SocketAddr>,
+    #[serde(default)]
+    pub tls6: Vec<SocketAddr>,
+    #[serde(default)]
+    pub quic: Vec<SocketAddr>,
+    #[serde(default)]
+    pub quic6: Vec<SocketAddr>,
+    pub domain: Option<String>,
+    pub ttl: Option<u32>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub certfile: Option<String>,
+    pub keyfile: Option<String>,
+    #[serde(default)]
+    pub ns_records: Vec<String>,
+    #[serde(default)]
+    pub soa_names: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// A TOML-only `udp`/`ns_records` entry, with nothing given on the
+    /// command line, must actually take effect: `fill_list!` has to treat
+    /// clap's "not set at all" (`value_source() == None`, since these fields
+    /// have no `default_value`) the same as "left at its default".
+    #[test]
+    fn config_file_fills_unset_list_fields() {
+        let matches = Options::command().get_matches_from(["dnssrc"]);
+        let mut options = Options::from_arg_matches(&matches).unwrap();
+        assert!(options.udp.is_empty());
+        assert!(options.ns_records.is_empty());
+
+        let file = ConfigFile {
+            udp: vec!["127.0.0.1:53".parse().unwrap()],
+            ns_records: vec!["ns1.example.com".to_string()],
+            ..ConfigFile::default()
+        };
+        options.apply_config_file(file, &matches);
+
+        assert_eq!(options.udp, vec!["127.0.0.1:53".parse().unwrap()]);
+        assert_eq!(options.ns_records, vec!["ns1.example.com".to_string()]);
+    }
+
+    /// An explicit CLI value always wins over `--config`, even for these
+    /// no-default-value list fields.
+    #[test]
+    fn config_file_does_not_override_explicit_cli_value() {
+        let matches = Options::command().get_matches_from(["dnssrc", "--udp", "0.0.0.0:53"]);
+        let mut options = Options::from_arg_matches(&matches).unwrap();
+
+        let file = ConfigFile {
+            udp: vec!["127.0.0.1:53".parse().unwrap()],
+            ..ConfigFile::default()
+        };
+        options.apply_config_file(file, &matches);
+
+        assert_eq!(options.udp, vec!["0.0.0.0:53".parse().unwrap()]);
+    }
 }
@@ -0,0 +1,92 @@
+//! Hot-reloadable TLS certificate/key material shared by the DoH, TLS, and
+//! QUIC listeners, so long-running test servers can pick up a renewed
+//! certificate without a restart.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use tracing::*;
+
+use crate::{read_cert, read_key};
+
+/// Re-reads `certfile`/`keyfile` on a timer and swaps the active
+/// `CertifiedKey` the listeners resolve against. A failed reload is logged
+/// and the previously loaded, still-valid material keeps being served.
+pub struct ReloadableCertResolver {
+    certfile: PathBuf,
+    keyfile: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCertResolver {
+    pub fn load(certfile: PathBuf, keyfile: PathBuf) -> anyhow::Result<Arc<Self>> {
+        let current = Self::read(&certfile, &keyfile)?;
+        Ok(Arc::new(ReloadableCertResolver {
+            certfile,
+            keyfile,
+            current: RwLock::new(Arc::new(current)),
+        }))
+    }
+
+    fn read(certfile: &std::path::Path, keyfile: &std::path::Path) -> anyhow::Result<CertifiedKey> {
+        let certs: Vec<Certificate> =
+            read_cert(certfile).map_err(|e| anyhow::anyhow!("{e}"))?;
+        let key: PrivateKey = read_key(keyfile).map_err(|e| anyhow::anyhow!("{e}"))?;
+        let signing_key = sign::any_supported_type(&key)
+            .map_err(|_| anyhow::anyhow!("unsupported private key type in {keyfile:?}"))?;
+        Ok(CertifiedKey::new(certs, signing_key))
+    }
+
+    fn reload(&self) {
+        match Self::read(&self.certfile, &self.keyfile) {
+            Ok(fresh) => {
+                *self.current.write().unwrap() = Arc::new(fresh);
+                info!("reloaded TLS certificate from {:?}", self.certfile);
+            }
+            Err(e) => {
+                warn!(
+                    "failed to reload TLS certificate from {:?}, keeping previous material: {e}",
+                    self.certfile
+                );
+            }
+        }
+    }
+
+    /// Build the `rustls::ServerConfig` the DoH/TLS/QUIC listeners register
+    /// with; it always resolves against whatever `current` holds.
+    pub fn server_config(self: &Arc<Self>, alpn: Vec<Vec<u8>>) -> Arc<ServerConfig> {
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = alpn;
+        Arc::new(config)
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Spawn the periodic reload timer that re-reads `certfile`/`keyfile` from
+/// disk every `interval`, picking up ACME-style renewals in place.
+pub fn spawn_reload_timer(resolver: Arc<ReloadableCertResolver>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await; // first tick is immediate; `load` already covers startup
+        loop {
+            ticker.tick().await;
+            resolver.reload();
+        }
+    });
+}